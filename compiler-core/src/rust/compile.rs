@@ -1,20 +1,254 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::files::SimpleFile;
+use codespan_reporting::term::{self, termcolor::Buffer};
 use itertools::Itertools;
 use smol_str::SmolStr;
 
 use crate::ast::{self as gleam};
 use crate::type_::{Type, TypeVar};
 
-pub fn compile(m: &gleam::TypedModule) -> String {
-    m.statements
+/// An error produced while compiling a Gleam module to Rust, carrying the
+/// source span of the construct that couldn't be compiled so that it can be
+/// reported back to the user instead of aborting the whole compile.
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub location: gleam::SrcSpan,
+    pub message: String,
+}
+
+impl CompileError {
+    fn unsupported(location: gleam::SrcSpan, feature: &str) -> CompileError {
+        CompileError {
+            location,
+            message: format!("`{feature}` codegen not yet supported"),
+        }
+    }
+
+    /// Renders this error as a labelled, colored snippet pointing at the
+    /// offending Gleam source.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let file = SimpleFile::new(path, source);
+        let span = self.location.start as usize..self.location.end as usize;
+        let diagnostic = Diagnostic::error()
+            .with_message(&self.message)
+            .with_labels(vec![Label::primary((), span)]);
+
+        let config = term::Config::default();
+        let mut buffer = Buffer::ansi();
+        term::emit(&mut buffer, &config, &file, &diagnostic)
+            .expect("emitting compile error diagnostic");
+        String::from_utf8(buffer.into_inner()).expect("compile error diagnostic is valid utf8")
+    }
+}
+
+pub fn compile(m: &gleam::TypedModule) -> Result<String, CompileError> {
+    let graph = TypeGraph::from_module(m);
+    let statements = m
+        .statements
         .iter()
-        .map(compile_statement)
-        .collect_vec()
-        .join("\n\n")
+        .map(|s| compile_statement(s, &graph))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(statements.join("\n\n"))
+}
+
+/// The module's custom types, related by which ones reference which in their
+/// fields. Used to decide which fields need wrapping in `Rc<>` so that
+/// recursive types still have a finite size in Rust.
+struct TypeGraph {
+    // Maps a custom type's name to the id of the strongly connected
+    // component it belongs to, for types that are part of a cycle. Types
+    // absent from this map are not recursive and never need wrapping.
+    scc_of: HashMap<SmolStr, usize>,
+    // Maps a constructor's name to the name of the custom type that declares
+    // it, so a pattern can be qualified with its enclosing enum.
+    constructor_owner: HashMap<SmolStr, SmolStr>,
+    // Custom types compiled to a Rust `enum` (more than one constructor),
+    // whose variant patterns must be written `Type::Variant` rather than the
+    // bare `Variant` that works for the single-constructor `struct` case.
+    multi_constructor_types: HashSet<SmolStr>,
+}
+
+impl TypeGraph {
+    fn from_module(m: &gleam::TypedModule) -> Self {
+        let type_names: HashSet<SmolStr> = m
+            .statements
+            .iter()
+            .filter_map(|s| match s {
+                gleam::Statement::CustomType { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut adjacency: HashMap<SmolStr, HashSet<SmolStr>> = HashMap::new();
+        let mut constructor_owner = HashMap::new();
+        let mut multi_constructor_types = HashSet::new();
+        for s in &m.statements {
+            if let gleam::Statement::CustomType {
+                name, constructors, ..
+            } = s
+            {
+                let mut refs = HashSet::new();
+                for c in constructors {
+                    constructor_owner.insert(c.name.clone(), name.clone());
+                    for a in &c.arguments {
+                        collect_type_refs(&a.type_, &type_names, &mut refs);
+                    }
+                }
+                if constructors.len() > 1 {
+                    multi_constructor_types.insert(name.clone());
+                }
+                adjacency.insert(name.clone(), refs);
+            }
+        }
+
+        let mut scc_of = HashMap::new();
+        for (id, component) in tarjan_scc(&adjacency).into_iter().enumerate() {
+            let is_cycle = component.len() > 1
+                || adjacency
+                    .get(&component[0])
+                    .map_or(false, |refs| refs.contains(&component[0]));
+            if is_cycle {
+                for name in component {
+                    scc_of.insert(name, id);
+                }
+            }
+        }
+
+        TypeGraph {
+            scc_of,
+            constructor_owner,
+            multi_constructor_types,
+        }
+    }
+
+    /// Whether `a` and `b` are part of the same cycle of custom types, and
+    /// so a field of type `a` inside the definition of `b` (or vice versa)
+    /// must be wrapped in `Rc<>` to have a finite size.
+    fn in_same_cycle(&self, a: &SmolStr, b: &SmolStr) -> bool {
+        match (self.scc_of.get(a), self.scc_of.get(b)) {
+            (Some(x), Some(y)) => x == y,
+            _ => false,
+        }
+    }
+
+    /// The Rust path a pattern or expression must use to name this
+    /// constructor: qualified with its enclosing type (`Type::Variant`) when
+    /// that type compiles to an `enum`, or the enclosing type's own name when
+    /// it compiles to a `struct` (`compile_custom_type` always names a
+    /// single-constructor struct after its *type*, even when the constructor
+    /// has a different name, so the bare constructor name is never the right
+    /// Rust path there).
+    fn constructor_path(&self, ctor_name: &SmolStr) -> String {
+        match self.constructor_owner.get(ctor_name) {
+            Some(type_name) if self.multi_constructor_types.contains(type_name) => {
+                format!("{type_name}::{ctor_name}")
+            }
+            Some(type_name) => type_name.to_string(),
+            None => ctor_name.to_string(),
+        }
+    }
+}
+
+fn collect_type_refs(t: &Arc<Type>, type_names: &HashSet<SmolStr>, out: &mut HashSet<SmolStr>) {
+    match &**t {
+        Type::App { name, args, .. } => {
+            if type_names.contains(name) {
+                out.insert(name.clone());
+            }
+            for a in args {
+                collect_type_refs(a, type_names, out);
+            }
+        }
+        Type::Var { type_ } => {
+            if let TypeVar::Link { type_ } = &*type_.borrow() {
+                collect_type_refs(type_, type_names, out);
+            }
+        }
+        Type::Fn { args, retrn } => {
+            for a in args {
+                collect_type_refs(a, type_names, out);
+            }
+            collect_type_refs(retrn, type_names, out);
+        }
+        Type::Tuple { elems } => {
+            for e in elems {
+                collect_type_refs(e, type_names, out);
+            }
+        }
+    }
 }
 
-fn compile_statement(s: &gleam::TypedStatement) -> String {
+fn tarjan_scc(adjacency: &HashMap<SmolStr, HashSet<SmolStr>>) -> Vec<Vec<SmolStr>> {
+    struct State {
+        index: HashMap<SmolStr, usize>,
+        low_link: HashMap<SmolStr, usize>,
+        on_stack: HashSet<SmolStr>,
+        stack: Vec<SmolStr>,
+        counter: usize,
+        sccs: Vec<Vec<SmolStr>>,
+    }
+
+    fn strong_connect(
+        v: &SmolStr,
+        adjacency: &HashMap<SmolStr, HashSet<SmolStr>>,
+        state: &mut State,
+    ) {
+        state.index.insert(v.clone(), state.counter);
+        state.low_link.insert(v.clone(), state.counter);
+        state.counter += 1;
+        state.stack.push(v.clone());
+        state.on_stack.insert(v.clone());
+
+        if let Some(neighbours) = adjacency.get(v) {
+            for w in neighbours {
+                if !state.index.contains_key(w) {
+                    strong_connect(w, adjacency, state);
+                    let low = state.low_link[v].min(state.low_link[w]);
+                    state.low_link.insert(v.clone(), low);
+                } else if state.on_stack.contains(w) {
+                    let low = state.low_link[v].min(state.index[w]);
+                    state.low_link.insert(v.clone(), low);
+                }
+            }
+        }
+
+        if state.low_link[v] == state.index[v] {
+            let mut component = Vec::new();
+            loop {
+                let w = state.stack.pop().expect("strongly connected component");
+                state.on_stack.remove(&w);
+                let done = &w == v;
+                component.push(w);
+                if done {
+                    break;
+                }
+            }
+            state.sccs.push(component);
+        }
+    }
+
+    let mut state = State {
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        on_stack: HashSet::new(),
+        stack: Vec::new(),
+        counter: 0,
+        sccs: Vec::new(),
+    };
+
+    for v in adjacency.keys() {
+        if !state.index.contains_key(v) {
+            strong_connect(v, adjacency, &mut state);
+        }
+    }
+
+    state.sccs
+}
+
+fn compile_statement(s: &gleam::TypedStatement, graph: &TypeGraph) -> Result<String, CompileError> {
     match s {
         gleam::Statement::Fn {
             location: _,
@@ -29,67 +263,41 @@ fn compile_statement(s: &gleam::TypedStatement) -> String {
         } => {
             let doc = compile_doc(doc);
             let public = compile_public(public);
-            let type_args = compile_type_args(arguments);
-            let arguments = compile_arguments(arguments);
-            let return_type = compile_type(return_type);
-            let body = compile_expression(body);
-            format!(
-                "{doc}{public}fn {name}{type_args}({arguments}) -> {return_type} {{\n{body}\n}}"
-            )
-        }
-        gleam::Statement::TypeAlias {
-            location,
-            alias,
-            parameters,
-            type_ast,
-            type_,
-            public,
-            doc,
-        } => todo!(),
+            let type_args = compile_type_args(arguments)?;
+            let rust_arguments = compile_arguments(arguments, graph)?;
+            let return_type = compile_type(return_type, None, graph, body.location())?;
+            let clones = analyse_last_use(body);
+            let mut fresh = 0;
+            let body = compile_expression(body, &clones, graph, &mut fresh)?;
+            Ok(format!(
+                "{doc}{public}fn {name}{type_args}({rust_arguments}) -> {return_type} {{\n{body}\n}}"
+            ))
+        }
+        gleam::Statement::TypeAlias { location, .. } => {
+            Err(CompileError::unsupported(*location, "type alias"))
+        }
         gleam::Statement::CustomType {
-            location,
+            location: _,
             name,
-            parameters,
+            parameters: _,
             public,
             constructors,
             doc,
-            opaque,
+            opaque: _,
             typed_parameters,
-        } => todo!(),
-        gleam::Statement::ExternalFn {
-            location,
-            public,
-            arguments,
-            name,
-            return_,
-            return_type,
-            module,
-            fun,
-            doc,
-        } => todo!(),
-        gleam::Statement::ExternalType {
-            location,
-            public,
-            name,
-            arguments,
-            doc,
-        } => todo!(),
-        gleam::Statement::Import {
-            location,
-            module,
-            as_name,
-            unqualified,
-            package,
-        } => todo!(),
-        gleam::Statement::ModuleConstant {
-            doc,
-            location,
-            public,
-            name,
-            annotation,
-            value,
-            type_,
-        } => todo!(),
+        } => compile_custom_type(name, public, doc, typed_parameters, constructors, graph),
+        gleam::Statement::ExternalFn { location, .. } => {
+            Err(CompileError::unsupported(*location, "external function"))
+        }
+        gleam::Statement::ExternalType { location, .. } => {
+            Err(CompileError::unsupported(*location, "external type"))
+        }
+        gleam::Statement::Import { location, .. } => {
+            Err(CompileError::unsupported(*location, "import"))
+        }
+        gleam::Statement::ModuleConstant { location, .. } => {
+            Err(CompileError::unsupported(*location, "module constant"))
+        }
     }
 }
 
@@ -107,20 +315,35 @@ fn compile_doc(d: &Option<SmolStr>) -> String {
     }
 }
 
-fn compile_arguments(a: &Vec<gleam::Arg<Arc<Type>>>) -> String {
-    a.iter()
-        .map(compile_argument)
-        .collect::<Vec<_>>()
-        .join(", ")
+fn compile_arguments(
+    a: &Vec<gleam::Arg<Arc<Type>>>,
+    graph: &TypeGraph,
+) -> Result<String, CompileError> {
+    Ok(a.iter()
+        .map(|a| compile_argument(a, graph))
+        .collect::<Result<Vec<_>, _>>()?
+        .join(", "))
 }
 
-fn compile_argument(a: &gleam::Arg<Arc<Type>>) -> String {
+fn compile_argument(a: &gleam::Arg<Arc<Type>>, graph: &TypeGraph) -> Result<String, CompileError> {
     let name = a.get_variable_name().unwrap_or(&SmolStr::from("_")).clone();
-    let typ = compile_type(&a.type_);
-    format!("{name}: {typ}")
+    let typ = compile_type(&a.type_, None, graph, a.location)?;
+    Ok(format!("{name}: {typ}"))
 }
 
-fn compile_type(t: &Arc<Type>) -> String {
+/// Compiles a Gleam type to its Rust equivalent. `current_type` is the name
+/// of the custom type whose definition is currently being compiled, if any;
+/// when a field type refers back into the same recursive cycle as
+/// `current_type` it is wrapped in `Rc<>` so the generated Rust type has a
+/// finite size. `location` is the source span of the construct this type
+/// annotates, used to report errors for type forms that aren't supported
+/// yet.
+fn compile_type(
+    t: &Arc<Type>,
+    current_type: Option<&SmolStr>,
+    graph: &TypeGraph,
+    location: gleam::SrcSpan,
+) -> Result<String, CompileError> {
     match &**t {
         Type::App {
             public: _,
@@ -128,173 +351,1045 @@ fn compile_type(t: &Arc<Type>) -> String {
             name,
             args,
         } => {
-            // TODO wrap with Rc<> if needed
-            if args.len() == 0 {
+            let base = if args.len() == 0 {
                 name.to_string()
             } else {
-                let args = args.iter().map(compile_type).collect::<Vec<_>>().join(", ");
+                let args = args
+                    .iter()
+                    .map(|a| compile_type(a, current_type, graph, location))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(", ");
                 format!("{name}<{args}>")
-            }
+            };
+            Ok(match current_type {
+                Some(current_type) if graph.in_same_cycle(name, current_type) => {
+                    format!("Rc<{base}>")
+                }
+                _ => base,
+            })
         }
         Type::Var { type_ } => match &*type_.borrow() {
-            TypeVar::Link { type_ } => compile_type(type_),
-            TypeVar::Generic { id } => format!("T{id}"),
-            TypeVar::Unbound { id } => format!("T{id}"),
+            TypeVar::Link { type_ } => compile_type(type_, current_type, graph, location),
+            TypeVar::Generic { id } => Ok(format!("T{id}")),
+            TypeVar::Unbound { id } => Ok(format!("T{id}")),
         },
-        Type::Fn { args, retrn } => todo!(),
-        Type::Tuple { elems } => todo!(),
+        Type::Fn { args, retrn } => {
+            let args = args
+                .iter()
+                .map(|a| compile_type(a, current_type, graph, location))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ");
+            let retrn = compile_type(retrn, current_type, graph, location)?;
+            Ok(format!("Rc<dyn Fn({args}) -> {retrn}>"))
+        }
+        Type::Tuple { .. } => Err(CompileError::unsupported(location, "tuple type")),
     }
 }
 
-fn compile_expression(b: &gleam::TypedExpr) -> String {
-    match b {
-        gleam::TypedExpr::Int {
-            location,
-            typ,
-            value,
-        } => value.to_string(),
-        gleam::TypedExpr::Float {
-            location,
-            typ,
-            value,
-        } => todo!(),
-        gleam::TypedExpr::String {
-            location,
-            typ,
-            value,
-        } => todo!(),
-        gleam::TypedExpr::Sequence {
-            location,
-            expressions,
+fn compile_custom_type_parameters(typed_parameters: &Vec<Arc<Type>>) -> Vec<String> {
+    typed_parameters
+        .iter()
+        .map(|t| match &**t {
+            Type::Var { type_ } => match &*type_.borrow() {
+                TypeVar::Generic { id } => format!("T{id}"),
+                _ => unreachable!("custom type parameters are always generic"),
+            },
+            _ => unreachable!("custom type parameters are always generic"),
+        })
+        .collect()
+}
+
+fn compile_custom_type(
+    name: &SmolStr,
+    public: &bool,
+    doc: &Option<SmolStr>,
+    typed_parameters: &Vec<Arc<Type>>,
+    constructors: &Vec<gleam::TypedRecordConstructor>,
+    graph: &TypeGraph,
+) -> Result<String, CompileError> {
+    let doc = compile_doc(doc);
+    let public = compile_public(public);
+    let typ_parameters = compile_custom_type_parameters(typed_parameters);
+    let typ_parameters = if typ_parameters.len() == 0 {
+        String::from("")
+    } else {
+        format!("<{}>", typ_parameters.join(", "))
+    };
+
+    if let [constructor] = constructors.as_slice() {
+        // A single-constructor type becomes a Rust struct rather than a
+        // one-variant enum.
+        let fields = compile_constructor_fields(constructor, name, graph)?;
+        if fields.len() == 0 {
+            Ok(format!("{doc}#[derive(Clone)]\n{public}struct {name}{typ_parameters};\n"))
+        } else {
+            let fields: String = fields.iter().map(|f| format!("\n\t{f},")).collect();
+            Ok(format!(
+                "{doc}#[derive(Clone)]\n{public}struct {name}{typ_parameters} {{{fields}\n}}\n"
+            ))
+        }
+    } else {
+        let constructors = constructors
+            .iter()
+            .map(|c| compile_constructor(c, name, graph))
+            .collect::<Result<Vec<_>, _>>()?
+            .iter()
+            .map(|c| format!("\n\t{c}"))
+            .collect::<String>();
+        Ok(format!(
+            "{doc}#[derive(Clone)]\n{public}enum {name}{typ_parameters} {{{constructors}\n}}\n"
+        ))
+    }
+}
+
+fn compile_constructor_fields(
+    c: &gleam::TypedRecordConstructor,
+    current_type: &SmolStr,
+    graph: &TypeGraph,
+) -> Result<Vec<String>, CompileError> {
+    c.arguments
+        .iter()
+        .enumerate()
+        .map(|(i, a)| compile_constructor_field(a, i, current_type, graph))
+        .collect()
+}
+
+fn compile_constructor_field(
+    a: &gleam::TypedRecordConstructorArg,
+    index: usize,
+    current_type: &SmolStr,
+    graph: &TypeGraph,
+) -> Result<String, CompileError> {
+    let name = a
+        .label
+        .clone()
+        .unwrap_or_else(|| SmolStr::from(format!("field{index}")));
+    let typ = compile_type(&a.type_, Some(current_type), graph, a.location)?;
+    Ok(format!("{name}: {typ}"))
+}
+
+fn compile_constructor(
+    c: &gleam::TypedRecordConstructor,
+    current_type: &SmolStr,
+    graph: &TypeGraph,
+) -> Result<String, CompileError> {
+    let name = c.name.clone();
+    let fields = compile_constructor_fields(c, current_type, graph)?;
+    Ok(if fields.len() == 0 {
+        format!("{name},")
+    } else {
+        let fields: String = fields.iter().map(|f| format!("\n\t\t{f},")).collect();
+        format!("{name} {{{fields}\n\t}},")
+    })
+}
+
+/// How a `Var` occurrence should be emitted, decided by the last-use
+/// analysis below.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CloneStrategy {
+    /// This is the final use of the binding in its scope: move it.
+    Move,
+    /// An earlier use of a non-`Copy` binding: clone the value.
+    Clone,
+}
+
+type CloneStrategies = HashMap<gleam::SrcSpan, CloneStrategy>;
+
+fn is_copy_type(t: &Arc<Type>) -> bool {
+    match &**t {
+        Type::App {
+            name, args, module, ..
         } => {
-            let body = expressions
-                .iter()
-                .map(compile_expression)
-                .collect_vec()
-                .join(";\n");
-            return format!("{body}");
+            args.is_empty()
+                && module == "gleam"
+                && matches!(name.as_str(), "Int" | "Float" | "Bool")
+        }
+        Type::Var { type_ } => match &*type_.borrow() {
+            TypeVar::Link { type_ } => is_copy_type(type_),
+            TypeVar::Generic { .. } | TypeVar::Unbound { .. } => false,
+        },
+        Type::Fn { .. } | Type::Tuple { .. } => false,
+    }
+}
+
+/// A backward (last-use) pass over a function body that decides, for every
+/// `Var` occurrence, whether the generated code should move the binding or
+/// `.clone()` it; no occurrence is ever emitted as `Rc::clone`, so a binding
+/// used from several branches is moved or cloned like any other. Walking from
+/// the end of the body towards the
+/// start, a set of "already seen" variable names is threaded through: the
+/// first time (in reverse) a name is encountered is its last use, so it can
+/// be moved instead of cloned.
+fn analyse_last_use(body: &gleam::TypedExpr) -> CloneStrategies {
+    let mut strategies = HashMap::new();
+    let mut seen = HashSet::new();
+    walk_backward(body, &mut seen, &mut strategies);
+    strategies
+}
+
+fn walk_backward(
+    expr: &gleam::TypedExpr,
+    seen: &mut HashSet<SmolStr>,
+    strategies: &mut CloneStrategies,
+) {
+    match expr {
+        gleam::TypedExpr::Sequence { expressions, .. } => {
+            for e in expressions.iter().rev() {
+                walk_backward(e, seen, strategies);
+            }
         }
-        gleam::TypedExpr::Pipeline {
-            location,
-            expressions,
-        } => todo!(),
         gleam::TypedExpr::Var {
             location,
             constructor,
             name,
         } => {
-            // TODO insert clones
-            format!("{name}")
+            let strategy = if is_copy_type(&constructor.type_) {
+                CloneStrategy::Move
+            } else if seen.contains(name) {
+                CloneStrategy::Clone
+            } else {
+                CloneStrategy::Move
+            };
+            strategies.insert(*location, strategy);
+            seen.insert(name.clone());
         }
-        gleam::TypedExpr::Fn {
-            location,
-            typ,
-            is_capture,
-            args,
-            body,
-            return_annotation,
-        } => todo!(),
-        gleam::TypedExpr::List {
-            location,
-            typ,
-            elements,
-            tail,
-        } => todo!(),
-        gleam::TypedExpr::Call {
-            location,
-            typ,
-            fun,
-            args,
+        gleam::TypedExpr::BinOp { left, right, .. } => {
+            walk_backward(right, seen, strategies);
+            walk_backward(left, seen, strategies);
+        }
+        gleam::TypedExpr::Call { fun, args, .. } => {
+            for a in args.iter().rev() {
+                walk_backward(&a.value, seen, strategies);
+            }
+            walk_backward(fun, seen, strategies);
+        }
+        gleam::TypedExpr::Case {
+            subjects, clauses, ..
         } => {
-            let fun = compile_expression(fun);
+            // Branches are mutually exclusive at runtime, so each one can
+            // independently move or clone the same outer binding without the
+            // others needing to agree: only one of them ever actually runs.
+            // A name still counts as "seen" going into the code before the
+            // match as a whole, though, if any branch uses it, since any one
+            // of them might run.
+            let mut union = seen.clone();
+            for clause in clauses {
+                let mut branch_seen = seen.clone();
+                walk_backward(&clause.then, &mut branch_seen, strategies);
+                union.extend(branch_seen);
+            }
+            *seen = union;
+
+            for subject in subjects.iter().rev() {
+                walk_backward(subject, seen, strategies);
+            }
+        }
+        gleam::TypedExpr::Pipeline { expressions, .. } => {
+            for e in expressions.iter().rev() {
+                walk_backward(e, seen, strategies);
+            }
+        }
+        gleam::TypedExpr::Fn { args, body, .. } => {
+            // The closure gets its own last-use analysis when
+            // `compile_closure` compiles its body, so there's nothing to
+            // recurse into here. But `compile_closure` also clones every
+            // non-`Copy` free variable right at the closure literal to capture
+            // it, which
+            // counts as a use from this outer analysis's point of view:
+            // whatever use of that name precedes the closure (i.e. comes
+            // later in this backward walk) must clone rather than move, so
+            // the binding is still there to capture.
+            let mut bound: HashSet<SmolStr> = args
+                .iter()
+                .filter_map(|a| a.get_variable_name().cloned())
+                .collect();
+            let mut free = HashMap::new();
+            collect_free_vars(body, &mut bound, &mut free);
+            seen.extend(free.into_keys());
+        }
+        // Other expression kinds are not yet compiled to Rust, so there is
+        // nothing to analyse in them yet.
+        _ => {}
+    }
+}
+
+/// A pattern occupying a column of the decision-tree matrix. `Wildcard`
+/// stands in for the columns synthesized when a constructor head is
+/// specialized against a row whose own pattern doesn't discriminate (a
+/// variable or discard), since there is no real sub-pattern to point to.
+enum PatternSlot<'a> {
+    Pattern(&'a gleam::TypedPattern),
+    Wildcard,
+}
+
+fn is_irrefutable(slot: &PatternSlot) -> bool {
+    match slot {
+        PatternSlot::Wildcard => true,
+        PatternSlot::Pattern(gleam::Pattern::Var { .. } | gleam::Pattern::Discard { .. }) => true,
+        PatternSlot::Pattern(_) => false,
+    }
+}
+
+/// The source span of a pattern that the decision tree doesn't know how to
+/// specialize on, for reporting an "unsupported" diagnostic instead of
+/// silently dropping the row it belongs to.
+fn pattern_location(p: &gleam::TypedPattern) -> gleam::SrcSpan {
+    match p {
+        gleam::Pattern::Int { location, .. }
+        | gleam::Pattern::Float { location, .. }
+        | gleam::Pattern::String { location, .. }
+        | gleam::Pattern::Var { location, .. }
+        | gleam::Pattern::Discard { location, .. }
+        | gleam::Pattern::Assign { location, .. }
+        | gleam::Pattern::List { location, .. }
+        | gleam::Pattern::Constructor { location, .. }
+        | gleam::Pattern::Tuple { location, .. }
+        | gleam::Pattern::BitString { location, .. } => *location,
+    }
+}
+
+/// One row of the pattern matrix: the remaining pattern columns still to be
+/// matched against the matching occurrences, the variable bindings already
+/// decided for this row, and which clause's body it leads to.
+struct ClauseRow<'a> {
+    patterns: Vec<PatternSlot<'a>>,
+    bindings: Vec<(SmolStr, String)>,
+    clause: usize,
+}
+
+fn record_field_label(label: &Option<SmolStr>, index: usize) -> SmolStr {
+    label
+        .clone()
+        .unwrap_or_else(|| SmolStr::from(format!("field{index}")))
+}
+
+fn constructor_field_labels(rows: &[ClauseRow], name: &SmolStr, arity: usize) -> Vec<SmolStr> {
+    for row in rows {
+        if let PatternSlot::Pattern(gleam::Pattern::Constructor {
+            name: n,
+            arguments,
+            ..
+        }) = &row.patterns[0]
+        {
+            if n == name {
+                return arguments
+                    .iter()
+                    .enumerate()
+                    .map(|(i, a)| record_field_label(&a.label, i))
+                    .collect();
+            }
+        }
+    }
+    (0..arity).map(|i| SmolStr::from(format!("field{i}"))).collect()
+}
+
+fn compile_case(
+    subjects: &Vec<gleam::TypedExpr>,
+    clauses: &Vec<gleam::TypedClause>,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    fresh: &mut usize,
+) -> Result<AnfExpr, CompileError> {
+    // Subjects are matched on directly inside `match subject { .. }`, which
+    // must be a single Rust expression; `compile_expression` can return a
+    // multi-statement `let _tN = ..;\n..` string for calls/pipelines/binops,
+    // so each subject is hoisted into its own `let` binding first and only
+    // the resulting atom is spliced into the `match`.
+    let mut prelude = Vec::new();
+    let occurrences = subjects
+        .iter()
+        .map(|s| atomize(s, clones, graph, &mut prelude, fresh))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bodies = clauses
+        .iter()
+        .map(|c| compile_expression(&c.then, clones, graph, fresh))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for c in clauses {
+        if c.guard.is_some() {
+            // A guard can make an otherwise-irrefutable row conditional
+            // (`n if n > 0 -> ..`), which the decision tree below has no way
+            // to express yet; compiling it anyway would make that row an
+            // unconditional arm and silently shadow every clause after it.
+            return Err(CompileError::unsupported(c.location, "clause guard"));
+        }
+    }
+
+    // Rows are built in clause order, with a clause's `alternative_patterns`
+    // (`1 | 2 -> ..`) immediately following its own `pattern` row, so they
+    // sit at the same priority as the clause they belong to rather than
+    // drifting to the end of the matrix and changing which arm fires first
+    // for overlapping patterns.
+    let mut rows: Vec<ClauseRow> = Vec::new();
+    for (i, c) in clauses.iter().enumerate() {
+        rows.push(ClauseRow {
+            patterns: c.pattern.iter().map(PatternSlot::Pattern).collect(),
+            bindings: Vec::new(),
+            clause: i,
+        });
+        for alternative in &c.alternative_patterns {
+            rows.push(ClauseRow {
+                patterns: alternative.iter().map(PatternSlot::Pattern).collect(),
+                bindings: Vec::new(),
+                clause: i,
+            });
+        }
+    }
+
+    let mut tree_fresh = 0;
+    let tree = build_decision_tree(&occurrences, rows, &bodies, graph, &mut tree_fresh)?;
+    Ok(wrap_prelude(prelude, AnfExpr::Value(tree)))
+}
+
+fn render_row(row: ClauseRow, bodies: &[String]) -> String {
+    let body = &bodies[row.clause];
+    if row.bindings.is_empty() {
+        body.clone()
+    } else {
+        let binds: String = row
+            .bindings
+            .iter()
+            .map(|(name, occurrence)| format!("let {name} = {occurrence};\n"))
+            .collect();
+        format!("{binds}{body}")
+    }
+}
+
+/// Compiles a Gleam `case` into a Rust `match` by repeatedly specializing the
+/// pattern matrix on one discriminating column at a time (Maranget's
+/// algorithm), rather than emitting one fully-expanded arm per clause. Each
+/// recursive call either (a) finds every remaining pattern in the leading
+/// column irrefutable, in which case it only records bindings and moves on
+/// to the next column, or (b) finds the leading column refutable, in which
+/// case it groups rows by the constructor/literal head they test and emits
+/// one `match` arm per head plus a default arm for the rest.
+fn build_decision_tree(
+    occurrences: &[String],
+    rows: Vec<ClauseRow>,
+    bodies: &[String],
+    graph: &TypeGraph,
+    fresh: &mut usize,
+) -> Result<String, CompileError> {
+    if occurrences.is_empty() || rows.iter().all(|r| r.patterns.is_empty()) {
+        let row = rows
+            .into_iter()
+            .next()
+            .expect("case expressions always have at least one clause");
+        return Ok(render_row(row, bodies));
+    }
+
+    if rows.iter().all(|r| is_irrefutable(&r.patterns[0])) {
+        let occurrence = occurrences[0].clone();
+        let rows = rows
+            .into_iter()
+            .map(|mut row| {
+                let pattern = row.patterns.remove(0);
+                if let PatternSlot::Pattern(gleam::Pattern::Var { name, .. }) = pattern {
+                    row.bindings.push((name.clone(), occurrence.clone()));
+                }
+                row
+            })
+            .collect();
+        return build_decision_tree(&occurrences[1..], rows, bodies, graph, fresh);
+    }
+
+    let occurrence = occurrences[0].clone();
+    let rest = &occurrences[1..];
+
+    let mut int_heads: Vec<SmolStr> = Vec::new();
+    let mut ctor_heads: Vec<(SmolStr, usize)> = Vec::new();
+    for row in &rows {
+        match &row.patterns[0] {
+            PatternSlot::Pattern(gleam::Pattern::Int { value, .. }) => {
+                if !int_heads.contains(value) {
+                    int_heads.push(value.clone());
+                }
+            }
+            PatternSlot::Pattern(gleam::Pattern::Constructor { name, arguments, .. }) => {
+                if !ctor_heads.iter().any(|(n, _)| n == name) {
+                    ctor_heads.push((name.clone(), arguments.len()));
+                }
+            }
+            PatternSlot::Pattern(p @ gleam::Pattern::String { .. })
+            | PatternSlot::Pattern(p @ gleam::Pattern::Float { .. })
+            | PatternSlot::Pattern(p @ gleam::Pattern::Tuple { .. })
+            | PatternSlot::Pattern(p @ gleam::Pattern::List { .. })
+            | PatternSlot::Pattern(p @ gleam::Pattern::BitString { .. })
+            | PatternSlot::Pattern(p @ gleam::Pattern::Assign { .. }) => {
+                return Err(CompileError::unsupported(pattern_location(p), "pattern kind"));
+            }
+            _ => {}
+        }
+    }
+
+    let has_default_rows = rows.iter().any(|r| is_irrefutable(&r.patterns[0]));
+    let mut arms = Vec::new();
+
+    if !int_heads.is_empty() {
+        for value in &int_heads {
+            let specialized = rows
+                .iter()
+                .filter_map(|row| match &row.patterns[0] {
+                    PatternSlot::Pattern(gleam::Pattern::Int { value: v, .. }) if v == value => {
+                        Some(ClauseRow {
+                            patterns: row.patterns[1..].iter().map(clone_slot).collect(),
+                            bindings: row.bindings.clone(),
+                            clause: row.clause,
+                        })
+                    }
+                    other if is_irrefutable(other) => {
+                        let mut bindings = row.bindings.clone();
+                        if let PatternSlot::Pattern(gleam::Pattern::Var { name, .. }) = other {
+                            bindings.push((name.clone(), occurrence.clone()));
+                        }
+                        Some(ClauseRow {
+                            patterns: row.patterns[1..].iter().map(clone_slot).collect(),
+                            bindings,
+                            clause: row.clause,
+                        })
+                    }
+                    _ => None,
+                })
+                .collect();
+            let body = build_decision_tree(rest, specialized, bodies, graph, fresh)?;
+            arms.push(format!("{value}i64 => {{\n{body}\n}}"));
+        }
+    } else {
+        for (name, arity) in &ctor_heads {
+            let labels = constructor_field_labels(&rows, name, *arity);
+            let field_vars: Vec<SmolStr> = (0..*arity)
+                .map(|_| {
+                    *fresh += 1;
+                    SmolStr::from(format!("_x{fresh}"))
+                })
+                .collect();
+
+            let specialized: Vec<ClauseRow> = rows
+                .iter()
+                .filter_map(|row| match &row.patterns[0] {
+                    PatternSlot::Pattern(gleam::Pattern::Constructor {
+                        name: n,
+                        arguments,
+                        ..
+                    }) if n == name => {
+                        let mut patterns: Vec<PatternSlot> = arguments
+                            .iter()
+                            .map(|a| PatternSlot::Pattern(&a.value))
+                            .collect();
+                        patterns.extend(row.patterns[1..].iter().map(clone_slot));
+                        Some(ClauseRow {
+                            patterns,
+                            bindings: row.bindings.clone(),
+                            clause: row.clause,
+                        })
+                    }
+                    other if is_irrefutable(other) => {
+                        let mut patterns: Vec<PatternSlot> =
+                            (0..*arity).map(|_| PatternSlot::Wildcard).collect();
+                        patterns.extend(row.patterns[1..].iter().map(clone_slot));
+                        let mut bindings = row.bindings.clone();
+                        if let PatternSlot::Pattern(gleam::Pattern::Var { name, .. }) = other {
+                            bindings.push((name.clone(), occurrence.clone()));
+                        }
+                        Some(ClauseRow {
+                            patterns,
+                            bindings,
+                            clause: row.clause,
+                        })
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            let mut occurrences: Vec<String> = field_vars.iter().map(|v| v.to_string()).collect();
+            occurrences.extend_from_slice(rest);
+            let body = build_decision_tree(&occurrences, specialized, bodies, graph, fresh)?;
+
+            let path = graph.constructor_path(name);
+            let pattern = if *arity == 0 {
+                path
+            } else {
+                let fields = labels
+                    .iter()
+                    .zip(field_vars.iter())
+                    .map(|(label, var)| format!("{label}: {var}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{path} {{ {fields} }}")
+            };
+            arms.push(format!("{pattern} => {{\n{body}\n}}"));
+        }
+    }
+
+    if has_default_rows {
+        let default_rows = rows
+            .into_iter()
+            .filter(|row| is_irrefutable(&row.patterns[0]))
+            .map(|mut row| {
+                let pattern = row.patterns.remove(0);
+                if let PatternSlot::Pattern(gleam::Pattern::Var { name, .. }) = pattern {
+                    row.bindings.push((name.clone(), occurrence.clone()));
+                }
+                row
+            })
+            .collect();
+        let body = build_decision_tree(rest, default_rows, bodies, graph, fresh)?;
+        arms.push(format!("_ => {{\n{body}\n}}"));
+    } else {
+        // Gleam's exhaustiveness checker already guarantees the heads above
+        // cover every possibility, so Rust can never actually reach this arm.
+        arms.push(String::from("_ => unreachable!(),"));
+    }
+
+    let arms = arms.join("\n");
+    Ok(format!("match {occurrence} {{\n{arms}\n}}"))
+}
+
+fn clone_slot<'a>(slot: &PatternSlot<'a>) -> PatternSlot<'a> {
+    match slot {
+        PatternSlot::Pattern(p) => PatternSlot::Pattern(p),
+        PatternSlot::Wildcard => PatternSlot::Wildcard,
+    }
+}
+
+/// An A-normal-form expression: a (possibly empty) chain of `let` bindings
+/// for hoisted sub-expressions, followed by a tail that is itself already
+/// safe to use directly as a Rust expression. Building this explicitly,
+/// rather than interleaving string formatting with hoisting, is what lets
+/// `Sequence` members and pipeline stages become Rust statements instead of
+/// having to squeeze everything into one expression. Assignments are not
+/// lowered: compiling one reports an unsupported-construct error.
+enum AnfExpr {
+    Value(String),
+    Let {
+        name: SmolStr,
+        value: String,
+        rest: Box<AnfExpr>,
+    },
+}
+
+fn wrap_prelude(prelude: Vec<(SmolStr, String)>, tail: AnfExpr) -> AnfExpr {
+    prelude
+        .into_iter()
+        .rev()
+        .fold(tail, |rest, (name, value)| AnfExpr::Let {
+            name,
+            value,
+            rest: Box::new(rest),
+        })
+}
+
+fn render_anf(anf: AnfExpr) -> String {
+    match anf {
+        AnfExpr::Value(v) => v,
+        AnfExpr::Let { name, value, rest } => {
+            format!("let {name} = {value};\n{}", render_anf(*rest))
+        }
+    }
+}
+
+/// Appends `anf`'s own `let` bindings to `prelude` and returns its tail
+/// value, so several lowered expressions can share one flat list of
+/// bindings instead of nesting.
+fn flatten(anf: AnfExpr, prelude: &mut Vec<(SmolStr, String)>) -> String {
+    match anf {
+        AnfExpr::Value(v) => v,
+        AnfExpr::Let { name, value, rest } => {
+            prelude.push((name, value));
+            flatten(*rest, prelude)
+        }
+    }
+}
+
+/// Lowers `expr` to an atom (a bare variable or literal) suitable for use as
+/// an operand of a call or binary operator, hoisting it into `prelude` as a
+/// fresh binding first if it isn't one already.
+fn atomize(
+    expr: &gleam::TypedExpr,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    prelude: &mut Vec<(SmolStr, String)>,
+    fresh: &mut usize,
+) -> Result<String, CompileError> {
+    if matches!(
+        expr,
+        gleam::TypedExpr::Var { .. } | gleam::TypedExpr::Int { .. }
+    ) {
+        return compile_expression(expr, clones, graph, fresh);
+    }
+    let anf = lower(expr, clones, graph, fresh)?;
+    let value = flatten(anf, prelude);
+    *fresh += 1;
+    let name = SmolStr::from(format!("_t{fresh}"));
+    prelude.push((name.clone(), value));
+    Ok(name.to_string())
+}
+
+/// Lowers a Gleam expression into A-normal form: every non-trivial
+/// sub-expression that a call, binop, or pipeline stage depends on is first
+/// hoisted into its own `let _tN = ...;` binding, so the operands
+/// `compile_expression` ultimately emits are always atoms.
+fn lower(
+    expr: &gleam::TypedExpr,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    fresh: &mut usize,
+) -> Result<AnfExpr, CompileError> {
+    match expr {
+        gleam::TypedExpr::BinOp { name, left, right, .. } => {
+            let mut prelude = Vec::new();
+            let left = atomize(left, clones, graph, &mut prelude, fresh)?;
+            let right = atomize(right, clones, graph, &mut prelude, fresh)?;
+            let op = compile_binop(name);
+            Ok(wrap_prelude(
+                prelude,
+                AnfExpr::Value(format!("({left} {op} {right})")),
+            ))
+        }
+        gleam::TypedExpr::Call { fun, args, .. } => {
+            let mut prelude = Vec::new();
+            let fun = atomize(fun, clones, graph, &mut prelude, fresh)?;
             let args = args
                 .iter()
-                .map(compile_call_arg)
-                .collect::<Vec<_>>()
+                .map(|a| atomize(&a.value, clones, graph, &mut prelude, fresh))
+                .collect::<Result<Vec<_>, _>>()?
                 .join(", ");
-            format!("{fun}({args})")
+            Ok(wrap_prelude(prelude, AnfExpr::Value(format!("{fun}({args})"))))
         }
-        gleam::TypedExpr::BinOp {
-            location,
-            typ,
-            name,
-            left,
-            right,
+        gleam::TypedExpr::Sequence { expressions, .. } => {
+            let mut prelude = Vec::new();
+            let mut tail = String::from("()");
+            for (i, e) in expressions.iter().enumerate() {
+                let anf = lower(e, clones, graph, fresh)?;
+                let rendered = flatten(anf, &mut prelude);
+                if i + 1 == expressions.len() {
+                    tail = rendered;
+                } else {
+                    prelude.push((SmolStr::from("_"), rendered));
+                }
+            }
+            Ok(wrap_prelude(prelude, AnfExpr::Value(tail)))
+        }
+        gleam::TypedExpr::Pipeline { expressions, .. } => {
+            lower_pipeline(expressions, clones, graph, fresh)
+        }
+        gleam::TypedExpr::Case {
+            subjects, clauses, ..
+        } => compile_case(subjects, clauses, clones, graph, fresh),
+        _ => Ok(AnfExpr::Value(compile_expression(expr, clones, graph, fresh)?)),
+    }
+}
+
+/// Lowers a Gleam pipeline `a |> f |> g` to `let _t1 = a; let _t2 = f(_t1);
+/// g(_t2)`: every stage but the last is bound to a fresh name, and the stage
+/// that follows it has the piped-in argument it already carries swapped out
+/// for a reference to that name, so the previous stage is evaluated exactly
+/// once rather than being recomputed inline.
+fn lower_pipeline(
+    expressions: &Vec<gleam::TypedExpr>,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    fresh: &mut usize,
+) -> Result<AnfExpr, CompileError> {
+    let mut prelude = Vec::new();
+    let mut previous: Option<(gleam::SrcSpan, String)> = None;
+
+    for (i, stage) in expressions.iter().enumerate() {
+        let rendered = match &previous {
+            Some((previous_location, previous_name)) => compile_piped_stage(
+                stage,
+                *previous_location,
+                previous_name,
+                clones,
+                graph,
+                &mut prelude,
+                fresh,
+            )?,
+            None => flatten(lower(stage, clones, graph, fresh)?, &mut prelude),
+        };
+
+        let is_last = i + 1 == expressions.len();
+        if is_last {
+            previous = Some((stage.location(), rendered));
+        } else {
+            *fresh += 1;
+            let name = SmolStr::from(format!("_t{fresh}"));
+            prelude.push((name.clone(), rendered));
+            previous = Some((stage.location(), name.to_string()));
+        }
+    }
+
+    let (_, tail) = previous.expect("pipelines always have at least one stage");
+    Ok(wrap_prelude(prelude, AnfExpr::Value(tail)))
+}
+
+/// Compiles one pipeline stage, replacing whichever of its call arguments is
+/// the piped-in value (identified by its source span matching the previous
+/// stage) with a reference to `previous_name` instead of recompiling it.
+fn compile_piped_stage(
+    expr: &gleam::TypedExpr,
+    previous_location: gleam::SrcSpan,
+    previous_name: &str,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    prelude: &mut Vec<(SmolStr, String)>,
+    fresh: &mut usize,
+) -> Result<String, CompileError> {
+    match expr {
+        gleam::TypedExpr::Call { fun, args, .. } => {
+            let fun = atomize(fun, clones, graph, prelude, fresh)?;
+            let args = args
+                .iter()
+                .map(|a| {
+                    if a.value.location() == previous_location {
+                        Ok(previous_name.to_string())
+                    } else {
+                        compile_call_arg(a, clones, graph, prelude, fresh)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(", ");
+            Ok(format!("{fun}({args})"))
+        }
+        _ => Ok(flatten(lower(expr, clones, graph, fresh)?, prelude)),
+    }
+}
+
+/// Compiles a Gleam anonymous function to a Rust closure matching the
+/// `Rc<dyn Fn(..) -> _>` shape `compile_type` gives `Type::Fn`. Gleam
+/// closures may be passed around and outlive the scope that created them, so
+/// every outer binding the body refers to is captured by `.clone()`ing it
+/// into a shadowing `let` just before the closure literal, rather than
+/// relying on whatever last-use decision the enclosing scope made for it;
+/// the `move` closure then simply takes ownership of those clones, leaving
+/// the original outer binding untouched for any code after the closure
+/// literal. `Copy` bindings are not cloned; the `move` closure copies them
+/// itself. `.clone()` (rather than `Rc::clone`) works uniformly here since
+/// every generated struct/enum derives `Clone` and `Rc<T>` always implements
+/// `Clone` regardless of `T`. The closure body is its own function scope, so
+/// it gets its own last-use analysis rather than reusing the enclosing
+/// scope's. `is_capture` (Gleam's `f(_, x)` shorthand) needs no special
+/// handling here: by the time the typed AST reaches this function the type
+/// checker has already desugared it into an ordinary `Fn` with a
+/// synthesized argument and body.
+fn compile_closure(
+    args: &Vec<gleam::Arg<Arc<Type>>>,
+    body: &gleam::TypedExpr,
+    graph: &TypeGraph,
+) -> Result<String, CompileError> {
+    let params: Vec<SmolStr> = args
+        .iter()
+        .map(|a| a.get_variable_name().cloned().unwrap_or_else(|| SmolStr::from("_")))
+        .collect();
+
+    let mut bound: HashSet<SmolStr> = params.iter().cloned().collect();
+    let mut free = HashMap::new();
+    collect_free_vars(body, &mut bound, &mut free);
+    let mut free: Vec<(SmolStr, Arc<Type>)> = free.into_iter().collect();
+    free.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // `Copy` bindings (`Int`, `Float`, `Bool`) are copied into the closure
+    // for free when it's created, same as any other Rust `move` closure, so
+    // cloning them here would be a no-op flagged by `clippy::clone_on_copy`.
+    let captures: String = free
+        .iter()
+        .filter(|(_, typ)| !is_copy_type(typ))
+        .map(|(name, _)| format!("let {name} = {name}.clone();\n"))
+        .collect();
+
+    let params = params.join(", ");
+    let clones = analyse_last_use(body);
+    let mut fresh = 0;
+    let body = compile_expression(body, &clones, graph, &mut fresh)?;
+    Ok(format!(
+        "{{\n{captures}Rc::new(move |{params}| {{\n{body}\n}})\n}}"
+    ))
+}
+
+/// Collects every `Var` `expr` refers to (name and type) that isn't bound by
+/// `bound`, threading pattern and closure-parameter bindings through nested
+/// scopes as it goes so that names bound locally (by a `case` pattern or a
+/// nested `Fn`) are never mistaken for captures of the enclosing closure.
+/// The type is carried along so the caller can tell which captures are
+/// `Copy` and need no clone.
+fn collect_free_vars(
+    expr: &gleam::TypedExpr,
+    bound: &mut HashSet<SmolStr>,
+    out: &mut HashMap<SmolStr, Arc<Type>>,
+) {
+    match expr {
+        gleam::TypedExpr::Var {
+            name, constructor, ..
         } => {
-            let name = compile_binop(name);
-            let left = compile_expression(left);
-            let right = compile_expression(right);
-            format!("({left} {name} {right})")
+            if !bound.contains(name) {
+                out.insert(name.clone(), constructor.type_.clone());
+            }
         }
-        gleam::TypedExpr::Assignment {
-            location,
-            typ,
+        gleam::TypedExpr::Sequence { expressions, .. } => {
+            expressions
+                .iter()
+                .for_each(|e| collect_free_vars(e, bound, out));
+        }
+        gleam::TypedExpr::Pipeline { expressions, .. } => {
+            expressions
+                .iter()
+                .for_each(|e| collect_free_vars(e, bound, out));
+        }
+        gleam::TypedExpr::BinOp { left, right, .. } => {
+            collect_free_vars(left, bound, out);
+            collect_free_vars(right, bound, out);
+        }
+        gleam::TypedExpr::Call { fun, args, .. } => {
+            collect_free_vars(fun, bound, out);
+            args.iter()
+                .for_each(|a| collect_free_vars(&a.value, bound, out));
+        }
+        gleam::TypedExpr::Case {
+            subjects, clauses, ..
+        } => {
+            subjects.iter().for_each(|s| collect_free_vars(s, bound, out));
+            for clause in clauses {
+                let mut bound = bound.clone();
+                clause
+                    .pattern
+                    .iter()
+                    .for_each(|p| collect_pattern_names(p, &mut bound));
+                collect_free_vars(&clause.then, &mut bound, out);
+            }
+        }
+        gleam::TypedExpr::Fn {
+            args: closure_args,
+            body,
+            ..
+        } => {
+            let mut bound = bound.clone();
+            for a in closure_args {
+                if let Some(name) = a.get_variable_name() {
+                    bound.insert(name.clone());
+                }
+            }
+            collect_free_vars(body, &mut bound, out);
+        }
+        _ => {}
+    }
+}
+
+fn collect_pattern_names(pattern: &gleam::TypedPattern, out: &mut HashSet<SmolStr>) {
+    match pattern {
+        gleam::Pattern::Var { name, .. } => {
+            out.insert(name.clone());
+        }
+        gleam::Pattern::Constructor { arguments, .. } => {
+            arguments
+                .iter()
+                .for_each(|a| collect_pattern_names(&a.value, out));
+        }
+        _ => {}
+    }
+}
+
+fn compile_expression(
+    b: &gleam::TypedExpr,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    fresh: &mut usize,
+) -> Result<String, CompileError> {
+    match b {
+        gleam::TypedExpr::Int {
+            location: _,
+            typ: _,
             value,
-            pattern,
-            kind,
-        } => todo!(),
-        gleam::TypedExpr::Try {
+        } => Ok(value.to_string()),
+        gleam::TypedExpr::Float { location, .. } => {
+            Err(CompileError::unsupported(*location, "float literal"))
+        }
+        gleam::TypedExpr::String { location, .. } => {
+            Err(CompileError::unsupported(*location, "string literal"))
+        }
+        gleam::TypedExpr::Sequence { .. } => {
+            let anf = lower(b, clones, graph, fresh)?;
+            Ok(render_anf(anf))
+        }
+        gleam::TypedExpr::Pipeline { .. } => {
+            let anf = lower(b, clones, graph, fresh)?;
+            Ok(render_anf(anf))
+        }
+        gleam::TypedExpr::Var {
             location,
-            typ,
-            value,
-            then,
-            pattern,
-        } => todo!(),
+            constructor: _,
+            name,
+        } => Ok(match clones.get(location) {
+            Some(CloneStrategy::Clone) => format!("{name}.clone()"),
+            Some(CloneStrategy::Move) | None => format!("{name}"),
+        }),
+        gleam::TypedExpr::Fn {
+            location: _,
+            typ: _,
+            is_capture: _,
+            args,
+            body,
+            return_annotation: _,
+        } => compile_closure(args, body, graph),
+        gleam::TypedExpr::List { location, .. } => {
+            Err(CompileError::unsupported(*location, "list literal"))
+        }
+        gleam::TypedExpr::Call { .. } => {
+            let anf = lower(b, clones, graph, fresh)?;
+            Ok(render_anf(anf))
+        }
+        gleam::TypedExpr::BinOp { .. } => {
+            let anf = lower(b, clones, graph, fresh)?;
+            Ok(render_anf(anf))
+        }
+        gleam::TypedExpr::Assignment { location, .. } => {
+            Err(CompileError::unsupported(*location, "assignment"))
+        }
+        gleam::TypedExpr::Try { location, .. } => {
+            Err(CompileError::unsupported(*location, "try"))
+        }
         gleam::TypedExpr::Case {
-            location,
-            typ,
+            location: _,
+            typ: _,
             subjects,
             clauses,
-        } => todo!(),
-        gleam::TypedExpr::RecordAccess {
-            location,
-            typ,
-            label,
-            index,
-            record,
-        } => todo!(),
-        gleam::TypedExpr::ModuleSelect {
-            location,
-            typ,
-            label,
-            module_name,
-            module_alias,
-            constructor,
-        } => todo!(),
-        gleam::TypedExpr::Tuple {
-            location,
-            typ,
-            elems,
-        } => todo!(),
-        gleam::TypedExpr::TupleIndex {
-            location,
-            typ,
-            index,
-            tuple,
-        } => todo!(),
-        gleam::TypedExpr::Todo {
-            location,
-            label,
-            typ,
-        } => todo!(),
-        gleam::TypedExpr::BitString {
-            location,
-            typ,
-            segments,
-        } => todo!(),
-        gleam::TypedExpr::RecordUpdate {
-            location,
-            typ,
-            spread,
-            args,
-        } => todo!(),
-        gleam::TypedExpr::Negate { location, value } => todo!(),
+        } => Ok(render_anf(compile_case(subjects, clauses, clones, graph, fresh)?)),
+        gleam::TypedExpr::RecordAccess { location, .. } => {
+            Err(CompileError::unsupported(*location, "record access"))
+        }
+        gleam::TypedExpr::ModuleSelect { location, .. } => {
+            Err(CompileError::unsupported(*location, "module select"))
+        }
+        gleam::TypedExpr::Tuple { location, .. } => {
+            Err(CompileError::unsupported(*location, "tuple literal"))
+        }
+        gleam::TypedExpr::TupleIndex { location, .. } => {
+            Err(CompileError::unsupported(*location, "tuple index"))
+        }
+        gleam::TypedExpr::Todo { location, .. } => {
+            Err(CompileError::unsupported(*location, "todo"))
+        }
+        gleam::TypedExpr::BitString { location, .. } => {
+            Err(CompileError::unsupported(*location, "bit string"))
+        }
+        gleam::TypedExpr::RecordUpdate { location, .. } => {
+            Err(CompileError::unsupported(*location, "record update"))
+        }
+        gleam::TypedExpr::Negate { location, .. } => {
+            Err(CompileError::unsupported(*location, "negation"))
+        }
     }
 }
 
-fn compile_call_arg(a: &gleam::CallArg<gleam::TypedExpr>) -> String {
+fn compile_call_arg(
+    a: &gleam::CallArg<gleam::TypedExpr>,
+    clones: &CloneStrategies,
+    graph: &TypeGraph,
+    prelude: &mut Vec<(SmolStr, String)>,
+    fresh: &mut usize,
+) -> Result<String, CompileError> {
     // TODO consider label, out of order?
-    compile_expression(&a.value)
+    atomize(&a.value, clones, graph, prelude, fresh)
 }
 
 fn compile_binop(op: &gleam::BinOp) -> String {
@@ -302,28 +1397,41 @@ fn compile_binop(op: &gleam::BinOp) -> String {
     String::from(op.name())
 }
 
-fn compile_type_args(a: &Vec<gleam::Arg<Arc<Type>>>) -> String {
-    let a = a
-        .iter()
-        .flat_map(|x| find_generics(&x.type_))
-        .map(|x| format!("T{x}"))
-        .collect_vec();
-    if a.len() == 0 {
+fn compile_type_args(a: &Vec<gleam::Arg<Arc<Type>>>) -> Result<String, CompileError> {
+    let mut ids = Vec::new();
+    for x in a {
+        ids.extend(find_generics(&x.type_, x.location)?);
+    }
+    let ids = ids.iter().map(|x| format!("T{x}")).collect_vec();
+    Ok(if ids.len() == 0 {
         String::from("")
     } else {
-        format!("<{}>", a.join(", "))
-    }
+        format!("<{}>", ids.join(", "))
+    })
 }
 
-fn find_generics(t: &Arc<Type>) -> Vec<u64> {
+fn find_generics(t: &Arc<Type>, location: gleam::SrcSpan) -> Result<Vec<u64>, CompileError> {
     match &**t {
-        Type::App { args, .. } => args.iter().flat_map(find_generics).collect_vec(),
+        Type::App { args, .. } => {
+            let mut ids = Vec::new();
+            for a in args {
+                ids.extend(find_generics(a, location)?);
+            }
+            Ok(ids)
+        }
         Type::Var { type_ } => match &*type_.borrow() {
-            TypeVar::Unbound { id } => vec![*id],
-            TypeVar::Link { type_ } => find_generics(type_),
-            TypeVar::Generic { id } => vec![*id],
+            TypeVar::Unbound { id } => Ok(vec![*id]),
+            TypeVar::Link { type_ } => find_generics(type_, location),
+            TypeVar::Generic { id } => Ok(vec![*id]),
         },
-        Type::Fn { args, retrn } => todo!(),
-        Type::Tuple { elems } => todo!(),
+        Type::Fn { args, retrn } => {
+            let mut ids = Vec::new();
+            for a in args {
+                ids.extend(find_generics(a, location)?);
+            }
+            ids.extend(find_generics(retrn, location)?);
+            Ok(ids)
+        }
+        Type::Tuple { .. } => Err(CompileError::unsupported(location, "tuple type")),
     }
 }